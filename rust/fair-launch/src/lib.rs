@@ -4,18 +4,18 @@ use {
     crate::utils::{assert_initialized, assert_owned_by, spl_token_transfer, TokenTransferParams, assert_data_valid, assert_derivation},
     anchor_lang::{
         prelude::*,
-        solana_program::{clock::UnixTimestamp, program_pack::Pack, system_program},
+        solana_program::{clock::UnixTimestamp, program::invoke, program::invoke_signed, program_pack::Pack, system_instruction, system_program},
         AnchorDeserialize, AnchorSerialize,
     },
     anchor_spl::token::{self, TokenAccount, Mint},
     spl_token::state::Account,
+    std::collections::BTreeMap,
 };
 
 pub const PREFIX: &str = "fair_launch";
 pub const TREASURY: &str = "treasury";
 pub const MINT: &str = "mint";
 pub const LOTTERY: &str="lottery";
-pub const MAX_GRANULARITY:u64 = 100;
 
 #[program]
 pub mod fair_launch {
@@ -57,12 +57,507 @@ pub mod fair_launch {
 
         Ok(())
     }
+
+    /// Computes the phase-2 median price. Can only be run once phase_two_end has passed.
+    pub fn calculate_fair_launch_phase_two<'info>(ctx: Context<'_, '_, '_, 'info, CalculateFairLaunchPhaseTwo<'info>>) -> ProgramResult {
+        let fair_launch = &mut ctx.accounts.fair_launch;
+        let clock = &ctx.accounts.clock;
+
+        if clock.unix_timestamp < fair_launch.data.phase_two_end {
+            return Err(ErrorCode::CannotCalculateMedianUntilPhaseTwoEnd.into());
+        }
+
+        if fair_launch.decided_median.is_some() {
+            return Err(ErrorCode::MedianAlreadyDecided.into());
+        }
+
+        let total_tickets = ctx.remaining_accounts.len() as u64;
+        if total_tickets == 0 {
+            return Err(ErrorCode::NoTicketsExist.into());
+        }
+        if total_tickets != fair_launch.number_tickets_sold_in_phase_1 {
+            return Err(ErrorCode::MustPassEveryPhaseOneTicket.into());
+        }
+
+        let mut histogram: BTreeMap<u64, u64> = BTreeMap::new();
+        for ticket_info in ctx.remaining_accounts.iter() {
+            assert_owned_by(ticket_info, ctx.program_id)?;
+            let ticket: FairLaunchTicket = FairLaunchTicket::try_deserialize(&mut ticket_info.data.borrow().as_ref())?;
+            if ticket.fair_launch != fair_launch.key() {
+                return Err(ErrorCode::TicketDoesNotMatchFairLaunch.into());
+            }
+
+            let count = histogram.entry(ticket.amount).or_insert(0);
+            *count = count.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?;
+        }
+
+        // Round ties down to the lower price tick by only ever accepting the first tick
+        // whose running count reaches or exceeds the half-point.
+        let half_point = total_tickets.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?.checked_div(2).ok_or(ErrorCode::NumericalOverflowError)?;
+        let mut running_total: u64 = 0;
+        let mut decided_median: Option<u64> = None;
+        let mut median: Vec<MedianTuple> = vec![];
+        for (price, count) in histogram.into_iter() {
+            median.push(MedianTuple(price, count));
+
+            running_total = running_total.checked_add(count).ok_or(ErrorCode::NumericalOverflowError)?;
+            if decided_median.is_none() && running_total >= half_point {
+                decided_median = Some(price);
+            }
+        }
+
+        // Requires enough prior ReallocFairLaunch calls to fit every tick discovered above.
+        let required_space = FAIR_LAUNCH_SPACE_VEC_START.checked_add(median.len().checked_mul(16).ok_or(ErrorCode::NumericalOverflowError)?).ok_or(ErrorCode::NumericalOverflowError)?;
+        if fair_launch.to_account_info().data_len() < required_space {
+            return Err(ErrorCode::FairLaunchAccountTooSmallForMedian.into());
+        }
+
+        fair_launch.median = median;
+        fair_launch.decided_median = decided_median;
+        fair_launch.number_tickets_remaining_in_phase_2 = total_tickets;
+
+        Ok(())
+    }
+
+    /// Grows the FairLaunch account to make room for new median tick buckets.
+    pub fn realloc_fair_launch(ctx: Context<ReallocFairLaunch>, entries_to_add: u16) -> ProgramResult {
+        let fair_launch_info = ctx.accounts.fair_launch.to_account_info();
+
+        let additional_space = (entries_to_add as usize).checked_mul(16).ok_or(ErrorCode::NumericalOverflowError)?;
+        let new_size = fair_launch_info.data_len().checked_add(additional_space).ok_or(ErrorCode::NumericalOverflowError)?;
+
+        fair_launch_info.realloc(new_size, false)?;
+
+        let new_minimum_balance = ctx.accounts.rent.minimum_balance(new_size);
+        let top_up = new_minimum_balance.saturating_sub(fair_launch_info.lamports());
+        if top_up > 0 {
+            invoke(
+                &system_instruction::transfer(ctx.accounts.payer.key, fair_launch_info.key, top_up),
+                &[ctx.accounts.payer.to_account_info(), fair_launch_info.clone(), ctx.accounts.system_program.to_account_info()],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Reservoir-samples `data.number_of_tokens` winners from tickets at/above `decided_median`.
+    pub fn update_fair_launch_lottery_bitmap<'info>(ctx: Context<'_, '_, '_, 'info, UpdateFairLaunchLotteryBitmap<'info>>) -> ProgramResult {
+        let fair_launch = &ctx.accounts.fair_launch;
+        let number_of_tokens = fair_launch.data.number_of_tokens;
+        let decided_median = fair_launch.decided_median.ok_or(ErrorCode::MedianNotYetDecided)?;
+
+        let bitmap_info = ctx.accounts.fair_launch_lottery_bitmap.to_account_info();
+        let mut account_data = bitmap_info.try_borrow_mut_data()?;
+        let (_header, rest) = account_data.split_at_mut(FAIR_LAUNCH_LOTTERY_SIZE);
+        let bits_len = (fair_launch.number_tickets_sold_in_phase_1 as usize).checked_div(8).ok_or(ErrorCode::NumericalOverflowError)? + 1;
+        let (bitmap_bytes, reservoir_bytes) = rest.split_at_mut(bits_len);
+
+        let mut bitmap_ones = ctx.accounts.fair_launch_lottery_bitmap.bitmap_ones as u64;
+        let mut tickets_seen = ctx.accounts.fair_launch_lottery_bitmap.tickets_seen;
+        let mut tickets_processed = ctx.accounts.fair_launch_lottery_bitmap.tickets_processed;
+        let mut rng_state = ctx.accounts.fair_launch_lottery_bitmap.rng_state;
+
+        if rng_state == 0 {
+            let slothashes_data = ctx.accounts.recent_slothashes.try_borrow_data()?;
+            let mut seed_input = slothashes_data[0..std::cmp::min(40, slothashes_data.len())].to_vec();
+            seed_input.extend_from_slice(fair_launch.to_account_info().key.as_ref());
+            let hash = anchor_lang::solana_program::hash::hash(&seed_input);
+            // xorshift64star requires a non-zero state.
+            rng_state = u64::from_le_bytes(hash.to_bytes()[0..8].try_into().unwrap()) | 1;
+        }
+
+        if ctx.remaining_accounts.len() % 2 != 0 {
+            return Err(ErrorCode::InvalidRemainingAccounts.into());
+        }
+
+        for pair in ctx.remaining_accounts.chunks(2) {
+            let seq_lookup_info = &pair[0];
+            let ticket_info = &pair[1];
+
+            assert_owned_by(seq_lookup_info, ctx.program_id)?;
+            assert_owned_by(ticket_info, ctx.program_id)?;
+
+            let seq_lookup: FairLaunchTicketSeqLookup = FairLaunchTicketSeqLookup::try_deserialize(&mut seq_lookup_info.data.borrow().as_ref())?;
+            let ticket: FairLaunchTicket = FairLaunchTicket::try_deserialize(&mut ticket_info.data.borrow().as_ref())?;
+
+            if seq_lookup.fair_launch_ticket != *ticket_info.key || seq_lookup.seq != ticket.seq || ticket.fair_launch != fair_launch.key() {
+                return Err(ErrorCode::TicketDoesNotMatchFairLaunch.into());
+            }
+
+            if ticket.seq != tickets_processed {
+                return Err(ErrorCode::TicketsNotProcessedInOrder.into());
+            }
+
+            tickets_processed = tickets_processed.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?;
+
+            if ticket.amount < decided_median {
+                // Below the median - never enters the lottery.
+                continue;
+            }
+
+            tickets_seen = tickets_seen.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?;
+
+            if bitmap_ones < number_of_tokens {
+                set_bit(bitmap_bytes, ticket.seq)?;
+                write_reservoir_slot(reservoir_bytes, bitmap_ones, ticket.seq);
+                bitmap_ones = bitmap_ones.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?;
+            } else {
+                rng_state = xorshift64star(rng_state);
+                let slot = rng_state % tickets_seen;
+                if slot < number_of_tokens {
+                    let evicted_seq = read_reservoir_slot(reservoir_bytes, slot);
+                    clear_bit(bitmap_bytes, evicted_seq)?;
+                    set_bit(bitmap_bytes, ticket.seq)?;
+                    write_reservoir_slot(reservoir_bytes, slot, ticket.seq);
+                }
+            }
+        }
+
+        if bitmap_ones > number_of_tokens {
+            return Err(ErrorCode::TooManyWinners.into());
+        }
+
+        ctx.accounts.fair_launch_lottery_bitmap.bitmap_ones = bitmap_ones as u32;
+        ctx.accounts.fair_launch_lottery_bitmap.tickets_seen = tickets_seen;
+        ctx.accounts.fair_launch_lottery_bitmap.tickets_processed = tickets_processed;
+        ctx.accounts.fair_launch_lottery_bitmap.rng_state = rng_state;
+
+        Ok(())
+    }
+
+    /// Commit `amount` into a brand new ticket for phase 1.
+    pub fn purchase_ticket<'info>(ctx: Context<'_, '_, '_, 'info, PurchaseTicket<'info>>, bump: u8, amount: u64) -> ProgramResult {
+        let fair_launch = &mut ctx.accounts.fair_launch;
+        let fair_launch_ticket = &mut ctx.accounts.fair_launch_ticket;
+        let fair_launch_ticket_seq_lookup = &mut ctx.accounts.fair_launch_ticket_seq_lookup;
+        let buyer = &ctx.accounts.buyer;
+        let treasury = &ctx.accounts.treasury;
+        let clock = &ctx.accounts.clock;
+
+        if amount == 0 {
+            return Err(ErrorCode::CannotPurchaseZeroAmount.into());
+        }
+
+        if clock.unix_timestamp < fair_launch.data.phase_one_start || clock.unix_timestamp > fair_launch.data.phase_one_end {
+            return Err(ErrorCode::CanOnlyPurchaseDuringPhaseOne.into());
+        }
+
+        pay_into_treasury(
+            fair_launch.treasury_mint,
+            treasury,
+            buyer,
+            amount,
+            &[],
+            &ctx.accounts.token_program,
+            ctx.remaining_accounts,
+        )?;
+
+        let seq = fair_launch.number_tickets_sold_in_phase_1;
+
+        fair_launch_ticket.fair_launch = fair_launch.key();
+        fair_launch_ticket.buyer = *buyer.key;
+        fair_launch_ticket.amount = amount;
+        fair_launch_ticket.state = FairLaunchTicketState::Unpunched;
+        fair_launch_ticket.bump = bump;
+        fair_launch_ticket.seq = seq;
+
+        fair_launch_ticket_seq_lookup.fair_launch_ticket = fair_launch_ticket.key();
+        fair_launch_ticket_seq_lookup.seq = seq;
+
+        fair_launch.number_tickets_sold_in_phase_1 = seq.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?;
+
+        Ok(())
+    }
+
+    /// Move a ticket's committed `amount` up or down, paying or refunding the delta.
+    pub fn adjust_ticket<'info>(ctx: Context<'_, '_, '_, 'info, AdjustTicket<'info>>, amount: u64) -> ProgramResult {
+        let fair_launch = &ctx.accounts.fair_launch;
+        let fair_launch_ticket = &mut ctx.accounts.fair_launch_ticket;
+        let buyer = &ctx.accounts.buyer;
+        let treasury = &ctx.accounts.treasury;
+        let clock = &ctx.accounts.clock;
+
+        if let FairLaunchTicketState::Withdrawn = fair_launch_ticket.state {
+            return Err(ErrorCode::TicketIsAlreadyWithdrawn.into());
+        }
+
+        let current_amount = fair_launch_ticket.amount;
+
+        if clock.unix_timestamp >= fair_launch.data.phase_two_end {
+            let decided_median = fair_launch.decided_median.ok_or(ErrorCode::MedianNotYetDecided)?;
+            if current_amount > decided_median {
+                if amount > current_amount || amount < decided_median {
+                    return Err(ErrorCode::CanOnlyAdjustDownToMedianInPhaseThree.into());
+                }
+            } else if amount > current_amount {
+                return Err(ErrorCode::CanOnlyAdjustDownInPhaseThree.into());
+            }
+        }
+
+        let treasury_seeds = [PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), TREASURY.as_bytes(), &[fair_launch.treasury_bump]];
+
+        if amount > current_amount {
+            let difference = amount.checked_sub(current_amount).ok_or(ErrorCode::NumericalOverflowError)?;
+            pay_into_treasury(
+                fair_launch.treasury_mint,
+                treasury,
+                buyer,
+                difference,
+                &[],
+                &ctx.accounts.token_program,
+                ctx.remaining_accounts,
+            )?;
+        } else if amount < current_amount {
+            let difference = current_amount.checked_sub(amount).ok_or(ErrorCode::NumericalOverflowError)?;
+            refund_from_treasury(
+                fair_launch.treasury_mint,
+                treasury,
+                buyer,
+                difference,
+                &treasury_seeds,
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+                ctx.remaining_accounts,
+            )?;
+        }
+
+        fair_launch_ticket.amount = amount;
+
+        Ok(())
+    }
+
+    /// Mints a winning ticket's token to `buyer_token_account`.
+    pub fn punch_ticket<'info>(ctx: Context<'_, '_, '_, 'info, PunchTicket<'info>>) -> ProgramResult {
+        let fair_launch = &mut ctx.accounts.fair_launch;
+        let fair_launch_ticket = &mut ctx.accounts.fair_launch_ticket;
+
+        if let FairLaunchTicketState::Punched = fair_launch_ticket.state {
+            return Err(ErrorCode::TicketAlreadyPunched.into());
+        }
+        if let FairLaunchTicketState::Withdrawn = fair_launch_ticket.state {
+            return Err(ErrorCode::TicketIsAlreadyWithdrawn.into());
+        }
+
+        if ctx.accounts.fair_launch_lottery_bitmap.tickets_processed != fair_launch.number_tickets_sold_in_phase_1 {
+            return Err(ErrorCode::LotteryBitmapNotYetComplete.into());
+        }
+
+        let bitmap_info = ctx.accounts.fair_launch_lottery_bitmap.to_account_info();
+        let is_winner = {
+            let bitmap_data = bitmap_info.try_borrow_data()?;
+            let bits_len = (fair_launch.number_tickets_sold_in_phase_1 as usize).checked_div(8).ok_or(ErrorCode::NumericalOverflowError)? + 1;
+            let bitmap_bytes = &bitmap_data[FAIR_LAUNCH_LOTTERY_SIZE..FAIR_LAUNCH_LOTTERY_SIZE + bits_len];
+            is_bit_set(bitmap_bytes, fair_launch_ticket.seq)?
+        };
+
+        if !is_winner {
+            return Err(ErrorCode::TicketIsNotAWinner.into());
+        }
+
+        let token_mint = fair_launch.token_mint;
+        let fair_launch_seeds = [PREFIX.as_bytes(), token_mint.as_ref(), &[fair_launch.bump]];
+
+        token::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.clone(),
+                token::MintTo {
+                    mint: ctx.accounts.token_mint.clone(),
+                    to: ctx.accounts.buyer_token_account.to_account_info(),
+                    authority: fair_launch.to_account_info(),
+                },
+                &[&fair_launch_seeds],
+            ),
+            1,
+        )?;
+
+        fair_launch_ticket.state = FairLaunchTicketState::Punched;
+        fair_launch.number_tickets_punched_in_phase_3 = fair_launch.number_tickets_punched_in_phase_3.checked_add(1).ok_or(ErrorCode::NumericalOverflowError)?;
+
+        Ok(())
+    }
+
+    /// Reclaims a non-winning ticket's full amount, or a winning ticket's excess over the median.
+    pub fn withdraw_funds<'info>(ctx: Context<'_, '_, '_, 'info, WithdrawFunds<'info>>) -> ProgramResult {
+        let fair_launch = &mut ctx.accounts.fair_launch;
+        let fair_launch_ticket = &mut ctx.accounts.fair_launch_ticket;
+        let buyer = &ctx.accounts.buyer;
+        let treasury = &ctx.accounts.treasury;
+
+        if let FairLaunchTicketState::Withdrawn = fair_launch_ticket.state {
+            return Err(ErrorCode::TicketIsAlreadyWithdrawn.into());
+        }
+
+        if ctx.accounts.fair_launch_lottery_bitmap.tickets_processed != fair_launch.number_tickets_sold_in_phase_1 {
+            return Err(ErrorCode::LotteryBitmapNotYetComplete.into());
+        }
+
+        let decided_median = fair_launch.decided_median.ok_or(ErrorCode::MedianNotYetDecided)?;
+
+        let bitmap_info = ctx.accounts.fair_launch_lottery_bitmap.to_account_info();
+        let is_winner = {
+            let bitmap_data = bitmap_info.try_borrow_data()?;
+            let bits_len = (fair_launch.number_tickets_sold_in_phase_1 as usize).checked_div(8).ok_or(ErrorCode::NumericalOverflowError)? + 1;
+            let bitmap_bytes = &bitmap_data[FAIR_LAUNCH_LOTTERY_SIZE..FAIR_LAUNCH_LOTTERY_SIZE + bits_len];
+            is_bit_set(bitmap_bytes, fair_launch_ticket.seq)?
+        };
+
+        let treasury_seeds = [PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), TREASURY.as_bytes(), &[fair_launch.treasury_bump]];
+
+        if is_winner {
+            let refund_amount = fair_launch_ticket.amount.checked_sub(decided_median).ok_or(ErrorCode::NumericalOverflowError)?;
+            if refund_amount == 0 {
+                return Err(ErrorCode::NoFundsToWithdraw.into());
+            }
+
+            refund_from_treasury(
+                fair_launch.treasury_mint,
+                treasury,
+                buyer,
+                refund_amount,
+                &treasury_seeds,
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+                ctx.remaining_accounts,
+            )?;
+
+            fair_launch_ticket.amount = decided_median;
+        } else {
+            let refund_amount = fair_launch_ticket.amount;
+
+            refund_from_treasury(
+                fair_launch.treasury_mint,
+                treasury,
+                buyer,
+                refund_amount,
+                &treasury_seeds,
+                &ctx.accounts.token_program,
+                &ctx.accounts.system_program,
+                ctx.remaining_accounts,
+            )?;
+
+            fair_launch_ticket.amount = 0;
+            fair_launch_ticket.state = FairLaunchTicketState::Withdrawn;
+            fair_launch.number_tickets_remaining_in_phase_2 = fair_launch.number_tickets_remaining_in_phase_2.checked_sub(1).ok_or(ErrorCode::NumericalOverflowError)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Pays `amount` from `buyer` into `treasury`.
+fn pay_into_treasury<'info>(
+    treasury_mint: Option<Pubkey>,
+    treasury: &AccountInfo<'info>,
+    buyer: &AccountInfo<'info>,
+    amount: u64,
+    buyer_signer_seeds: &[&[u8]],
+    token_program: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> ProgramResult {
+    match treasury_mint {
+        Some(treasury_mint) => {
+            let payer_token_account_info = remaining_accounts.get(0).ok_or(ErrorCode::TreasuryMintAccountRequired)?;
+            let payer_token_account: Account = assert_initialized(payer_token_account_info)?;
+            if payer_token_account.mint != treasury_mint {
+                return Err(ErrorCode::MintMismatch.into());
+            }
+
+            spl_token_transfer(TokenTransferParams {
+                source: payer_token_account_info.clone(),
+                destination: treasury.clone(),
+                authority: buyer.clone(),
+                authority_signer_seeds: buyer_signer_seeds,
+                token_program: token_program.clone(),
+                amount,
+            })
+        }
+        None => invoke(
+            &system_instruction::transfer(buyer.key, treasury.key, amount),
+            &[buyer.clone(), treasury.clone()],
+        ),
+    }
+}
+
+/// Refunds `amount` from `treasury` back to `buyer`.
+fn refund_from_treasury<'info>(
+    treasury_mint: Option<Pubkey>,
+    treasury: &AccountInfo<'info>,
+    buyer: &AccountInfo<'info>,
+    amount: u64,
+    treasury_signer_seeds: &[&[u8]],
+    token_program: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> ProgramResult {
+    match treasury_mint {
+        Some(_) => {
+            let payer_token_account_info = remaining_accounts.get(0).ok_or(ErrorCode::TreasuryMintAccountRequired)?;
+
+            spl_token_transfer(TokenTransferParams {
+                source: treasury.clone(),
+                destination: payer_token_account_info.clone(),
+                authority: treasury.clone(),
+                authority_signer_seeds: treasury_signer_seeds,
+                token_program: token_program.clone(),
+                amount,
+            })
+        }
+        None => invoke_signed(
+            &system_instruction::transfer(treasury.key, buyer.key, amount),
+            &[treasury.clone(), buyer.clone(), system_program.clone()],
+            &[treasury_signer_seeds],
+        ),
+    }
+}
+
+/// xorshift64* PRNG; `state` must never be zero.
+fn xorshift64star(mut state: u64) -> u64 {
+    state ^= state >> 12;
+    state ^= state << 25;
+    state ^= state >> 27;
+    state.wrapping_mul(0x2545F4914F6CDD1D)
+}
+
+fn set_bit(bitmap: &mut [u8], seq: u64) -> ProgramResult {
+    let (byte, mask) = bit_position(bitmap, seq)?;
+    bitmap[byte] |= mask;
+    Ok(())
+}
+
+fn clear_bit(bitmap: &mut [u8], seq: u64) -> ProgramResult {
+    let (byte, mask) = bit_position(bitmap, seq)?;
+    bitmap[byte] &= !mask;
+    Ok(())
+}
+
+fn is_bit_set(bitmap: &[u8], seq: u64) -> std::result::Result<bool, ProgramError> {
+    let (byte, mask) = bit_position(bitmap, seq)?;
+    Ok(bitmap[byte] & mask != 0)
+}
+
+fn bit_position(bitmap: &[u8], seq: u64) -> std::result::Result<(usize, u8), ProgramError> {
+    let byte = (seq as usize).checked_div(8).ok_or(ErrorCode::NumericalOverflowError)?;
+    if byte >= bitmap.len() {
+        return Err(ErrorCode::NumericalOverflowError.into());
+    }
+    Ok((byte, 1 << (seq % 8)))
+}
+
+fn write_reservoir_slot(reservoir: &mut [u8], slot: u64, seq: u64) {
+    let start = (slot as usize) * 8;
+    reservoir[start..start + 8].copy_from_slice(&seq.to_le_bytes());
+}
+
+fn read_reservoir_slot(reservoir: &[u8], slot: u64) -> u64 {
+    let start = (slot as usize) * 8;
+    u64::from_le_bytes(reservoir[start..start + 8].try_into().unwrap())
 }
 
 #[derive(Accounts)]
 #[instruction(bump: u8, treasury_bump: u8, token_mint_bump: u8, data: FairLaunchData)]
 pub struct InitializeFairLaunch<'info> {
-    #[account(init, seeds=[PREFIX.as_bytes(), token_mint.key.as_ref()], payer=payer, bump=bump, space=FAIR_LAUNCH_SPACE_VEC_START+16*(((data.price_range_end - data.price_range_start).checked_div(data.tick_size).ok_or(ErrorCode::NumericalOverflowError)? + 1)) as usize)]
+    // No tick buckets are pre-allocated; ReallocFairLaunch grows this account as phase 2 fills them.
+    #[account(init, seeds=[PREFIX.as_bytes(), token_mint.key.as_ref()], payer=payer, bump=bump, space=FAIR_LAUNCH_SPACE_VEC_START)]
     fair_launch: ProgramAccount<'info, FairLaunch>,
     #[account(init, seeds=[PREFIX.as_bytes(), authority.key.as_ref(), MINT.as_bytes(), data.uuid.as_bytes()], mint::authority=fair_launch, mint::decimals=0, payer=payer, bump=token_mint_bump)]
     token_mint: CpiAccount<'info, Mint>,
@@ -96,13 +591,39 @@ pub struct StartPhaseThree<'info> {
     authority: AccountInfo<'info>,
 }
 
+/// Can only run once phase_two_end has passed.
+#[derive(Accounts)]
+pub struct CalculateFairLaunchPhaseTwo<'info> {
+    #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=authority)]
+    fair_launch: ProgramAccount<'info, FairLaunch>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+/// Grows the `FairLaunch` account by `entries_to_add` MedianTuple slots.
+#[derive(Accounts)]
+#[instruction(entries_to_add: u16)]
+pub struct ReallocFairLaunch<'info> {
+    #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=authority)]
+    fair_launch: ProgramAccount<'info, FairLaunch>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(mut, signer)]
+    payer: AccountInfo<'info>,
+    #[account(address = system_program::ID)]
+    system_program: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
+}
+
 /// Can only create the fair launch lottery bitmap after phase 1 has ended.
 #[derive(Accounts)]
 #[instruction(bump: u8)]
 pub struct CreateFairLaunchLotteryBitmap<'info> {
     #[account(seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=authority)]
     fair_launch: ProgramAccount<'info, FairLaunch>,
-    #[account(init, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), LOTTERY.as_bytes()],  payer=payer, bump=bump, space= FAIR_LAUNCH_LOTTERY_SIZE + (fair_launch.number_tickets_sold_in_phase_1.checked_div(8).ok_or(ErrorCode::NumericalOverflowError)? as usize) + 1)]
+    // Trailing space holds the winner bitmap followed by a `number_of_tokens`-sized reservoir.
+    #[account(init, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), LOTTERY.as_bytes()],  payer=payer, bump=bump, space= FAIR_LAUNCH_LOTTERY_SIZE + (fair_launch.number_tickets_sold_in_phase_1.checked_div(8).ok_or(ErrorCode::NumericalOverflowError)? as usize) + 1 + (fair_launch.data.number_of_tokens as usize) * 8)]
     fair_launch_lottery_bitmap: ProgramAccount<'info, FairLaunchLotteryBitmap>,
     #[account(signer)]
     authority: AccountInfo<'info>,
@@ -122,6 +643,9 @@ pub struct UpdateFairLaunchLotteryBitmap<'info> {
     fair_launch_lottery_bitmap: ProgramAccount<'info, FairLaunchLotteryBitmap>,
     #[account(signer)]
     authority: AccountInfo<'info>,
+    /// Seeds the PRNG on the first strip; ignored afterward.
+    #[account(address = anchor_lang::solana_program::sysvar::slot_hashes::ID)]
+    recent_slothashes: AccountInfo<'info>,
 }
 
 /// Can only purchase a ticket in phase 1.
@@ -135,7 +659,7 @@ pub struct PurchaseTicket<'info> {
     #[account(init, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), buyer.key.as_ref()],  payer=payer, bump=bump, space=FAIR_LAUNCH_TICKET_SIZE)]
     fair_launch_ticket: ProgramAccount<'info, FairLaunchTicket>,
     #[account(init, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), &fair_launch.number_tickets_sold_in_phase_1.to_le_bytes()],  payer=payer, bump=bump, space=FAIR_LAUNCH_TICKET_SEQ_SIZE)]
-    fair_launch_ticket_seq_lookup: ProgramAccount<'info, FairLaunchTicket>,
+    fair_launch_ticket_seq_lookup: ProgramAccount<'info, FairLaunchTicketSeqLookup>,
     #[account(mut, signer, constraint= (treasury.owner == &spl_token::id() && buyer.owner == &spl_token::id()) || (treasury.owner != &spl_token::id() && buyer.data_is_empty() && buyer.lamports() > 0) )]
     buyer: AccountInfo<'info>,
     #[account(mut, signer)]
@@ -145,6 +669,7 @@ pub struct PurchaseTicket<'info> {
     #[account(address = system_program::ID)]
     system_program: AccountInfo<'info>,
     rent: Sysvar<'info, Rent>,
+    clock: Sysvar<'info, Clock>,
 }
 
 
@@ -157,7 +682,7 @@ pub struct PurchaseTicket<'info> {
 pub struct AdjustTicket<'info> {
     #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), buyer.key.as_ref()],  bump=fair_launch_ticket.bump,has_one=buyer, has_one=fair_launch)]
     fair_launch_ticket: ProgramAccount<'info, FairLaunchTicket>,
-    #[account(seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump)]
+    #[account(seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=treasury)]
     fair_launch: ProgramAccount<'info, FairLaunch>,
     #[account(mut)]
     treasury: AccountInfo<'info>,
@@ -167,27 +692,52 @@ pub struct AdjustTicket<'info> {
     token_program: AccountInfo<'info>,
     #[account(address = system_program::ID)]
     system_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
 }
 #[derive(Accounts)]
 pub struct PunchTicket<'info> {
     #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), buyer.key.as_ref()], bump=fair_launch_ticket.bump, has_one=buyer, has_one=fair_launch)]
     fair_launch_ticket: ProgramAccount<'info, FairLaunchTicket>,
-    #[account(seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=token_mint)]
+    #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=token_mint)]
     fair_launch: ProgramAccount<'info, FairLaunch>,
+    #[account(seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), LOTTERY.as_bytes()], bump=fair_launch_lottery_bitmap.bump, has_one=fair_launch)]
+    fair_launch_lottery_bitmap: ProgramAccount<'info, FairLaunchLotteryBitmap>,
     #[account(mut, signer)]
     buyer: AccountInfo<'info>,
     #[account(mut, constraint=&buyer_token_account.mint == token_mint.key && buyer_token_account.to_account_info().owner == &spl_token::id())]
     buyer_token_account: CpiAccount<'info, TokenAccount>,
-    #[account(seeds=[PREFIX.as_bytes(), fair_launch.authority.as_ref(), MINT.as_bytes(), fair_launch.data.uuid.as_bytes()], bump=fair_launch.token_mint_bump)]
+    #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.authority.as_ref(), MINT.as_bytes(), fair_launch.data.uuid.as_bytes()], bump=fair_launch.token_mint_bump)]
     token_mint: AccountInfo<'info>,
     #[account(address = spl_token::id())]
     token_program: AccountInfo<'info>,
 }
 
+/// Reclaims a non-winning ticket's deposit, or a winning ticket's excess over the median.
+#[derive(Accounts)]
+pub struct WithdrawFunds<'info> {
+    #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), buyer.key.as_ref()], bump=fair_launch_ticket.bump, has_one=buyer, has_one=fair_launch)]
+    fair_launch_ticket: ProgramAccount<'info, FairLaunchTicket>,
+    #[account(mut, seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref()], bump=fair_launch.bump, has_one=treasury)]
+    fair_launch: ProgramAccount<'info, FairLaunch>,
+    #[account(seeds=[PREFIX.as_bytes(), fair_launch.token_mint.as_ref(), LOTTERY.as_bytes()], bump=fair_launch_lottery_bitmap.bump, has_one=fair_launch)]
+    fair_launch_lottery_bitmap: ProgramAccount<'info, FairLaunchLotteryBitmap>,
+    #[account(mut)]
+    treasury: AccountInfo<'info>,
+    #[account(mut, signer)]
+    buyer: AccountInfo<'info>,
+    #[account(address = spl_token::id())]
+    token_program: AccountInfo<'info>,
+    #[account(address = system_program::ID)]
+    system_program: AccountInfo<'info>,
+}
+
 pub const FAIR_LAUNCH_LOTTERY_SIZE: usize = 8 + // discriminator
 32 + // fair launch
 1 + // bump
-4; // size of bitmask ones
+4 + // size of bitmask ones
+8 + // rng_state
+8 + // tickets_seen
+8; // tickets_processed
 
 pub const FAIR_LAUNCH_SPACE_VEC_START: usize = 8 + // discriminator
 32 + // token_mint
@@ -261,9 +811,15 @@ pub struct FairLaunch {
 #[account]
 pub struct FairLaunchLotteryBitmap {
     pub fair_launch: Pubkey,
-    pub bump: u8, 
-    /// This must be exactly the number of winners and is incremented precisely in each strip addition
-    pub bitmap_ones: u32 
+    pub bump: u8,
+    /// Number of winning bits currently set.
+    pub bitmap_ones: u32,
+    /// Zero until the PRNG is seeded from SlotHashes on the first strip.
+    pub rng_state: u64,
+    /// Eligible tickets streamed through so far, across all strips.
+    pub tickets_seen: u64,
+    /// Tickets streamed through so far, across all strips, eligible or not.
+    pub tickets_processed: u64,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -315,12 +871,50 @@ pub enum ErrorCode {
     CannotGiveZeroTokens,
     #[msg("Invalid price ranges")]
     InvalidPriceRanges,
-    #[msg("With this tick size and price range, you will have too many ticks(>" + MAX_GRANULARITY + ") - choose less granularity")]
-    TooMuchGranularityInRange,
     #[msg("Cannot use a tick size with a price range that results in a remainder when doing (end-start)/ticksize")]
     CannotUseTickSizeThatGivesRemainder,
     #[msg("Derived key invalid")]
     DerivedKeyInvalid,
     #[msg("Treasury Already Exists")]
-    TreasuryAlreadyExists
+    TreasuryAlreadyExists,
+    #[msg("Cannot calculate the median until phase two has ended")]
+    CannotCalculateMedianUntilPhaseTwoEnd,
+    #[msg("No tickets exist to calculate a median from")]
+    NoTicketsExist,
+    #[msg("Ticket does not belong to this fair launch")]
+    TicketDoesNotMatchFairLaunch,
+    #[msg("Decided median has not been calculated yet")]
+    MedianNotYetDecided,
+    #[msg("Remaining accounts must be passed in (seq lookup, ticket) pairs")]
+    InvalidRemainingAccounts,
+    #[msg("This strip would select more winners than number_of_tokens allows")]
+    TooManyWinners,
+    #[msg("Cannot purchase a ticket with a zero amount")]
+    CannotPurchaseZeroAmount,
+    #[msg("Can only purchase a ticket during phase one")]
+    CanOnlyPurchaseDuringPhaseOne,
+    #[msg("This ticket has already been withdrawn")]
+    TicketIsAlreadyWithdrawn,
+    #[msg("In phase three, a ticket above the decided median can only be adjusted down to the decided median")]
+    CanOnlyAdjustDownToMedianInPhaseThree,
+    #[msg("In phase three, a ticket can only be adjusted down, never up")]
+    CanOnlyAdjustDownInPhaseThree,
+    #[msg("A treasury_mint token account for the buyer must be passed in remaining_accounts")]
+    TreasuryMintAccountRequired,
+    #[msg("Call ReallocFairLaunch to grow the FairLaunch account before writing more median ticks")]
+    FairLaunchAccountTooSmallForMedian,
+    #[msg("There are no funds left to withdraw for this ticket")]
+    NoFundsToWithdraw,
+    #[msg("The median has already been decided for this fair launch")]
+    MedianAlreadyDecided,
+    #[msg("Every ticket sold in phase 1 must be passed in remaining_accounts")]
+    MustPassEveryPhaseOneTicket,
+    #[msg("This ticket has already been punched")]
+    TicketAlreadyPunched,
+    #[msg("This ticket did not win the lottery")]
+    TicketIsNotAWinner,
+    #[msg("UpdateFairLaunchLotteryBitmap has not yet processed every phase 1 ticket")]
+    LotteryBitmapNotYetComplete,
+    #[msg("Tickets must be streamed through UpdateFairLaunchLotteryBitmap in increasing seq order with no repeats")]
+    TicketsNotProcessedInOrder,
 }